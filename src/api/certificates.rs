@@ -0,0 +1,62 @@
+use std::ops::Deref;
+
+use jsonwebtoken::{EncodingKey, DecodingKey};
+use jsonwebtoken::errors::Error as JwtError;
+
+
+pub struct PrivateKey(EncodingKey);
+
+impl PrivateKey {
+    pub fn from_rsa_pem(pem: &[u8]) -> Result<Self, JwtError> {
+        Ok(Self(EncodingKey::from_rsa_pem(pem)?))
+    }
+
+    /// Builds a shared-secret signing key for HS256/HS384/HS512, for deployments
+    /// that sign with a shared secret instead of an RSA keypair.
+    pub fn from_hmac_secret(secret: &[u8]) -> Self {
+        Self(EncodingKey::from_secret(secret))
+    }
+
+    /// Builds an EC signing key for ES256, for deployments that sign with an
+    /// elliptic-curve keypair instead of an RSA keypair.
+    pub fn from_ec_pem(pem: &[u8]) -> Result<Self, JwtError> {
+        Ok(Self(EncodingKey::from_ec_pem(pem)?))
+    }
+}
+
+impl Deref for PrivateKey {
+    type Target = EncodingKey;
+
+    fn deref(&self) -> &EncodingKey {
+        &self.0
+    }
+}
+
+
+pub struct PublicKey(DecodingKey);
+
+impl PublicKey {
+    pub fn from_rsa_pem(pem: &[u8]) -> Result<Self, JwtError> {
+        Ok(Self(DecodingKey::from_rsa_pem(pem)?))
+    }
+
+    /// Builds the verification-side counterpart of `PrivateKey::from_hmac_secret`,
+    /// for verifying HS256/HS384/HS512 tokens signed with a shared secret.
+    pub fn from_hmac_secret(secret: &[u8]) -> Self {
+        Self(DecodingKey::from_secret(secret))
+    }
+
+    /// Builds the verification-side counterpart of `PrivateKey::from_ec_pem`,
+    /// for verifying ES256 tokens signed with an elliptic-curve keypair.
+    pub fn from_ec_pem(pem: &[u8]) -> Result<Self, JwtError> {
+        Ok(Self(DecodingKey::from_ec_pem(pem)?))
+    }
+}
+
+impl Deref for PublicKey {
+    type Target = DecodingKey;
+
+    fn deref(&self) -> &DecodingKey {
+        &self.0
+    }
+}
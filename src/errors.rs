@@ -0,0 +1,23 @@
+use failure::Fail;
+
+
+#[derive(Debug, Fail)]
+pub enum TokenErrors {
+    #[fail(display = "{}: {}", _0, _1)]
+    TokenEncodingFailed(String, String),
+
+    #[fail(display = "{}: {}", _0, _1)]
+    TokenDecodingFailed(String, String),
+
+    #[fail(display = "{}", _0)]
+    TokenExpired(String),
+
+    #[fail(display = "{}", _0)]
+    TokenNotYetValid(String),
+
+    #[fail(display = "{}", _0)]
+    InvalidSignature(String),
+
+    #[fail(display = "{}", _0)]
+    MalformedToken(String),
+}
@@ -1,12 +1,113 @@
-use jsonwebtoken::{encode, decode, Header, Algorithm, Validation};
+use std::collections::{HashMap, HashSet};
+
+use jsonwebtoken::{encode, decode, decode_header, dangerous_insecure_decode, Header, Algorithm, Validation};
+use jsonwebtoken::errors::{Error as JwtError, ErrorKind as JwtErrorKind};
 
 use failure::Error;
 
 use crate::utils::helpers::compute_timestamp_in_seconds;
-use crate::errors::TokenErrors::{TokenEncodingFailed, TokenDecodingFailed};
+use crate::errors::TokenErrors::{TokenEncodingFailed, TokenDecodingFailed, TokenExpired, TokenNotYetValid, InvalidSignature, MalformedToken};
 use crate::api::certificates::{PrivateKey, PublicKey};
 
 
+/// Options mapped onto the underlying `jsonwebtoken::Validation` used on decode.
+///
+/// Unset fields fall back to `jsonwebtoken`'s own defaults (no leeway, `exp`
+/// checked, `nbf` not checked, `iss`/`aud`/`sub` unchecked).
+#[derive(Debug, Clone, Default)]
+pub struct TokenValidation {
+    leeway: Option<u64>,
+    validate_exp: Option<bool>,
+    validate_nbf: Option<bool>,
+    iss: Option<String>,
+    aud: Option<HashSet<String>>,
+    sub: Option<String>,
+}
+
+impl TokenValidation {
+    pub fn new(leeway: Option<u64>, validate_exp: Option<bool>, validate_nbf: Option<bool>, iss: Option<String>, aud: Option<HashSet<String>>, sub: Option<String>) -> Self {
+        Self { leeway, validate_exp, validate_nbf, iss, aud, sub }
+    }
+}
+
+fn build_validation(algorithm: Algorithm, validation: Option<TokenValidation>) -> Validation {
+    // `Validation::new` pins `validation.algorithms` to exactly this algorithm, so a
+    // token signed HS256 is rejected outright when the caller expects RS256 and vice
+    // versa, rather than silently verifying under the wrong key type.
+    let mut built = Validation::new(algorithm);
+    if let Some(options) = validation {
+        if let Some(leeway) = options.leeway {
+            built.leeway = leeway;
+        }
+        if let Some(validate_exp) = options.validate_exp {
+            built.validate_exp = validate_exp;
+        }
+        if let Some(validate_nbf) = options.validate_nbf {
+            built.validate_nbf = validate_nbf;
+        }
+        if options.iss.is_some() {
+            built.iss = options.iss;
+        }
+        if options.aud.is_some() {
+            built.aud = options.aud;
+        }
+        if options.sub.is_some() {
+            built.sub = options.sub;
+        }
+    }
+    built
+}
+
+/// Turns a `jsonwebtoken` decode failure into the specific `TokenErrors` variant
+/// it corresponds to, so a web frontend can tell an expired token from a bad
+/// signature from a not-yet-valid (`nbf`) token and respond accordingly.
+fn map_decode_error(error: JwtError) -> Error {
+    match error.kind() {
+        JwtErrorKind::ExpiredSignature => TokenExpired("token has expired".to_string()).into(),
+        JwtErrorKind::ImmatureSignature => TokenNotYetValid("token is not yet valid".to_string()).into(),
+        JwtErrorKind::InvalidSignature => InvalidSignature("token signature is invalid".to_string()).into(),
+        JwtErrorKind::InvalidToken | JwtErrorKind::Base64(_) | JwtErrorKind::Json(_) | JwtErrorKind::Utf8(_) => {
+            MalformedToken("token is malformed".to_string()).into()
+        }
+        _ => TokenDecodingFailed("Unable to decode token".to_string(), error.to_string()).into(),
+    }
+}
+
+/// The `Header` and claims recovered from a successfully verified token, kept
+/// together since callers that rotate keys or branch on `alg` need the header
+/// in addition to the claims themselves.
+#[derive(Debug, PartialEq)]
+pub struct DecodedToken<T> {
+    header: Header,
+    claims: T,
+}
+
+impl<T> DecodedToken<T> {
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+    pub fn claims(&self) -> &T {
+        &self.claims
+    }
+}
+
+fn select_key_for_token<'a>(keyring: &'a HashMap<String, PublicKey>, token: &str) -> Result<&'a PublicKey, Error> {
+    let header = decode_header(token);
+    if header.is_err() {
+        return Err(map_decode_error(header.err().unwrap()));
+    };
+    let key_id = header.ok().unwrap().kid;
+    let key_id = match key_id {
+        Some(key_id) => key_id,
+        None => return Err(TokenDecodingFailed("Unable to decode token".to_string(), "token has no kid header".to_string()).into()),
+    };
+    match keyring.get(&key_id) {
+        Some(public_certificate) => Ok(public_certificate),
+        None => Err(TokenDecodingFailed("Unable to decode token".to_string(), format!("no key registered for kid `{}`", key_id)).into()),
+    }
+}
+
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct ClientClaims {
     sub: Vec<u8>,
@@ -60,8 +161,10 @@ impl ClientClaims {
     }
 }
 
-pub fn encode_client_token(private_certificate: &PrivateKey, user_id: &[u8], _buf: Option<Vec<u8>>, _ref: u64, exp: Option<i64>, nbf: Option<i64>, iat: Option<i64>) -> Result<String, Error> {
-    let header = Header::new(Algorithm::RS256);
+pub fn encode_client_token(private_certificate: &PrivateKey, user_id: &[u8], _buf: Option<Vec<u8>>, _ref: u64, exp: Option<i64>, nbf: Option<i64>, iat: Option<i64>, algorithm: Option<Algorithm>, key_id: Option<String>) -> Result<String, Error> {
+    let algorithm = algorithm.unwrap_or(Algorithm::RS256);
+    let mut header = Header::new(algorithm);
+    header.kid = key_id;
     let claims = ClientClaims::new(user_id.to_vec(), _buf, _ref, exp, nbf, iat);
     let token = encode(&header, &claims, &private_certificate);
     if token.is_err() {
@@ -72,13 +175,33 @@ pub fn encode_client_token(private_certificate: &PrivateKey, user_id: &[u8], _bu
     Ok(token)
 }
 
-pub fn decode_client_token(public_certificate: &PublicKey, token: &str) -> Result<ClientClaims, Error> {
-    let validation = Validation::new(Algorithm::RS256);
+pub fn decode_client_token(public_certificate: &PublicKey, token: &str, algorithm: Option<Algorithm>, validation: Option<TokenValidation>) -> Result<DecodedToken<ClientClaims>, Error> {
+    let validation = build_validation(algorithm.unwrap_or(Algorithm::RS256), validation);
 
     let result = decode::<ClientClaims>(token, &public_certificate, &validation);
     if result.is_err() {
-        let msg = result.err().unwrap().to_string();
-        return Err(TokenDecodingFailed("Unable to decode token".to_string(), msg).into());
+        return Err(map_decode_error(result.err().unwrap()));
+    };
+    let result = result.ok().unwrap();
+    Ok(DecodedToken { header: result.header, claims: result.claims })
+}
+
+/// Decodes a token whose `Header::kid` identifies which entry of `keyring` signed
+/// it, so a deployment can rotate signing keys by publishing the new public key
+/// alongside the old one instead of invalidating every outstanding token.
+pub fn decode_client_token_with_keyring(keyring: &HashMap<String, PublicKey>, token: &str, algorithm: Option<Algorithm>, validation: Option<TokenValidation>) -> Result<DecodedToken<ClientClaims>, Error> {
+    let public_certificate = select_key_for_token(keyring, token)?;
+    decode_client_token(public_certificate, token, algorithm, validation)
+}
+
+/// Parses the claims out of `token` without verifying its signature, expiry, or
+/// any other validation check. Useful for reading `sub`/`_ref` to pick the right
+/// verification key before the real `decode_client_token` call, or for logging
+/// the contents of an expired/otherwise-rejected token.
+pub fn decode_client_token_insecure(token: &str) -> Result<ClientClaims, Error> {
+    let result = dangerous_insecure_decode::<ClientClaims>(token);
+    if result.is_err() {
+        return Err(map_decode_error(result.err().unwrap()));
     };
     let claims = result.ok().unwrap();
     let claims = claims.claims;
@@ -144,8 +267,10 @@ impl ServerClaims {
     }
 }
 
-pub fn encode_server_token(private_certificate: &PrivateKey, user_id: &[u8], _client: Option<Vec<u8>>, _server: Option<Vec<u8>>, _ref: u64, exp: Option<i64>, nbf: Option<i64>, iat: Option<i64>) -> Result<String, Error> {
-    let header = Header::new(Algorithm::RS256);
+pub fn encode_server_token(private_certificate: &PrivateKey, user_id: &[u8], _client: Option<Vec<u8>>, _server: Option<Vec<u8>>, _ref: u64, exp: Option<i64>, nbf: Option<i64>, iat: Option<i64>, algorithm: Option<Algorithm>, key_id: Option<String>) -> Result<String, Error> {
+    let algorithm = algorithm.unwrap_or(Algorithm::RS256);
+    let mut header = Header::new(algorithm);
+    header.kid = key_id;
     let claims = ServerClaims::new(user_id.to_vec(), _client, _server, _ref, exp, nbf, iat);
 
     let token = encode(&header, &claims, &private_certificate);
@@ -157,13 +282,33 @@ pub fn encode_server_token(private_certificate: &PrivateKey, user_id: &[u8], _cl
     Ok(token)
 }
 
-pub fn decode_server_token(public_certificate: &PublicKey, token: &str) -> Result<ServerClaims, Error> {
-    let validation = Validation::new(Algorithm::RS256);
+pub fn decode_server_token(public_certificate: &PublicKey, token: &str, algorithm: Option<Algorithm>, validation: Option<TokenValidation>) -> Result<DecodedToken<ServerClaims>, Error> {
+    let validation = build_validation(algorithm.unwrap_or(Algorithm::RS256), validation);
 
     let result = decode::<ServerClaims>(token, &public_certificate, &validation);
     if result.is_err() {
-        let msg = result.err().unwrap().to_string();
-        return Err(TokenDecodingFailed("Unable to decode token".to_string(), msg).into());
+        return Err(map_decode_error(result.err().unwrap()));
+    };
+    let result = result.ok().unwrap();
+    Ok(DecodedToken { header: result.header, claims: result.claims })
+}
+
+/// Decodes a token whose `Header::kid` identifies which entry of `keyring` signed
+/// it, so a deployment can rotate signing keys by publishing the new public key
+/// alongside the old one instead of invalidating every outstanding token.
+pub fn decode_server_token_with_keyring(keyring: &HashMap<String, PublicKey>, token: &str, algorithm: Option<Algorithm>, validation: Option<TokenValidation>) -> Result<DecodedToken<ServerClaims>, Error> {
+    let public_certificate = select_key_for_token(keyring, token)?;
+    decode_server_token(public_certificate, token, algorithm, validation)
+}
+
+/// Parses the claims out of `token` without verifying its signature, expiry, or
+/// any other validation check. Useful for reading `sub`/`_ref` to pick the right
+/// verification key before the real `decode_server_token` call, or for logging
+/// the contents of an expired/otherwise-rejected token.
+pub fn decode_server_token_insecure(token: &str) -> Result<ServerClaims, Error> {
+    let result = dangerous_insecure_decode::<ServerClaims>(token);
+    if result.is_err() {
+        return Err(map_decode_error(result.err().unwrap()));
     };
     let claims = result.ok().unwrap();
     let claims = claims.claims;
@@ -176,6 +321,7 @@ pub fn decode_server_token(public_certificate: &PublicKey, token: &str) -> Resul
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::errors::TokenErrors;
 
     #[test]
     fn validate_token_validity() {
@@ -226,5 +372,108 @@ mod tests {
         assert_eq!(claims.nbf, claims.iat);
     }
 
+    fn hmac_keypair(secret: &[u8]) -> (PrivateKey, PublicKey) {
+        (PrivateKey::from_hmac_secret(secret), PublicKey::from_hmac_secret(secret))
+    }
+
+    #[test]
+    fn decode_rejects_algorithm_confusion() {
+        let (private_key, public_key) = hmac_keypair(b"client-secret");
+        let token = encode_client_token(&private_key, b"user", None, 1u64, None, None, None, Some(Algorithm::HS256), None).unwrap();
+
+        let result = decode_client_token(&public_key, &token, Some(Algorithm::RS256), None);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn decode_accepts_matching_algorithm() {
+        let (private_key, public_key) = hmac_keypair(b"client-secret");
+        let token = encode_client_token(&private_key, b"user", None, 1u64, None, None, None, Some(Algorithm::HS256), None).unwrap();
+
+        let decoded = decode_client_token(&public_key, &token, Some(Algorithm::HS256), None).unwrap();
+        assert_eq!(decoded.claims().sub(), &b"user".to_vec());
+    }
+
+    #[test]
+    fn decode_honors_leeway_and_nbf_toggle() {
+        let (private_key, public_key) = hmac_keypair(b"server-secret");
+        let iat = compute_timestamp_in_seconds();
+        let nbf = iat + 10; // not yet valid for another 10 seconds
+        let token = encode_server_token(&private_key, b"user", None, None, 1u64, None, Some(nbf), Some(iat), Some(Algorithm::HS256), None).unwrap();
+
+        // jsonwebtoken does not check `nbf` by default, so the token verifies anyway
+        assert!(decode_server_token(&public_key, &token, Some(Algorithm::HS256), None).is_ok());
+
+        // turning `nbf` checking on rejects the not-yet-valid token
+        let strict = TokenValidation::new(None, None, Some(true), None, None, None);
+        assert!(decode_server_token(&public_key, &token, Some(Algorithm::HS256), Some(strict)).is_err());
+
+        // a leeway at least as large as the gap lets it through
+        let lenient = TokenValidation::new(Some(30), None, Some(true), None, None, None);
+        assert!(decode_server_token(&public_key, &token, Some(Algorithm::HS256), Some(lenient)).is_ok());
+    }
+
+    #[test]
+    fn keyring_decode_selects_key_by_kid() {
+        let mut keyring: HashMap<String, PublicKey> = HashMap::new();
+        let (private_key, public_key) = hmac_keypair(b"rotated-secret");
+        keyring.insert("key-1".to_string(), public_key);
+
+        let token = encode_client_token(&private_key, b"user", None, 1u64, None, None, None, Some(Algorithm::HS256), Some("key-1".to_string())).unwrap();
+        let decoded = decode_client_token_with_keyring(&keyring, &token, Some(Algorithm::HS256), None).unwrap();
+        assert_eq!(decoded.claims().sub(), &b"user".to_vec());
+    }
 
+    #[test]
+    fn keyring_decode_rejects_missing_and_unknown_kid() {
+        let keyring: HashMap<String, PublicKey> = HashMap::new();
+        let (private_key, _) = hmac_keypair(b"rotated-secret");
+
+        let token_without_kid = encode_client_token(&private_key, b"user", None, 1u64, None, None, None, Some(Algorithm::HS256), None).unwrap();
+        assert!(decode_client_token_with_keyring(&keyring, &token_without_kid, Some(Algorithm::HS256), None).is_err());
+
+        let token_with_unknown_kid = encode_client_token(&private_key, b"user", None, 1u64, None, None, None, Some(Algorithm::HS256), Some("missing".to_string())).unwrap();
+        assert!(decode_client_token_with_keyring(&keyring, &token_with_unknown_kid, Some(Algorithm::HS256), None).is_err());
+    }
+
+    #[test]
+    fn insecure_decode_reads_claims_of_an_expired_token() {
+        let (private_key, public_key) = hmac_keypair(b"client-secret");
+        let iat = compute_timestamp_in_seconds();
+        let token = encode_client_token(&private_key, b"user", None, 1u64, Some(iat - 10), None, Some(iat - 100), Some(Algorithm::HS256), None).unwrap();
+
+        assert!(decode_client_token(&public_key, &token, Some(Algorithm::HS256), None).is_err());
+
+        let claims = decode_client_token_insecure(&token).unwrap();
+        assert_eq!(claims.sub(), &b"user".to_vec());
+    }
+
+    #[test]
+    fn insecure_decode_rejects_garbage_input() {
+        let result = decode_client_token_insecure("not-a-jwt");
+        match result {
+            Err(err) => assert!(err.downcast_ref::<TokenErrors>().map_or(false, |kind| matches!(kind, TokenErrors::MalformedToken(_)))),
+            Ok(_) => panic!("expected garbage input to be rejected"),
+        }
+    }
+
+    #[test]
+    fn decode_distinguishes_expiry_and_signature_failures() {
+        let (private_key, public_key) = hmac_keypair(b"client-secret");
+        let iat = compute_timestamp_in_seconds();
+        let expired_token = encode_client_token(&private_key, b"user", None, 1u64, Some(iat - 10), None, Some(iat - 100), Some(Algorithm::HS256), None).unwrap();
+        let result = decode_client_token(&public_key, &expired_token, Some(Algorithm::HS256), None);
+        match result {
+            Err(err) => assert!(err.downcast_ref::<TokenErrors>().map_or(false, |kind| matches!(kind, TokenErrors::TokenExpired(_)))),
+            Ok(_) => panic!("expected an expired token to be rejected"),
+        }
+
+        let (wrong_private_key, _) = hmac_keypair(b"a-different-secret");
+        let mismatched_token = encode_client_token(&wrong_private_key, b"user", None, 1u64, None, None, None, Some(Algorithm::HS256), None).unwrap();
+        let result = decode_client_token(&public_key, &mismatched_token, Some(Algorithm::HS256), None);
+        match result {
+            Err(err) => assert!(err.downcast_ref::<TokenErrors>().map_or(false, |kind| matches!(kind, TokenErrors::InvalidSignature(_)))),
+            Ok(_) => panic!("expected a token signed with a different secret to be rejected"),
+        }
+    }
 }
\ No newline at end of file